@@ -4,14 +4,120 @@
 
 #![allow(non_upper_case_globals)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
 
-use serde_json::{json, Value as JsonValue};
+use serde::ser::{SerializeMap, Serializer as _};
+use serde_json::{json, Serializer, Value as JsonValue};
 
 use crate::database::Database;
 use crate::metrics::Metric;
 use crate::Lifetime;
 
+/// The outcome of a size-limited snapshot.
+///
+/// See [`StorageManager::snapshot_as_json_with_limit`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// The total size, in bytes, of the metrics kept in the snapshot.
+    pub size: usize,
+    /// The number of metrics that were dropped because including them would
+    /// have exceeded the requested size budget.
+    pub dropped_metrics: u32,
+}
+
+/// The serialized-size estimate used by [`StorageManager::snapshot_as_json_with_limit`]
+/// for a single metric entry: its key plus its rendered JSON value.
+fn serialized_entry_size(metric_name: &str, value: &JsonValue) -> usize {
+    metric_name.len() + value.to_string().len()
+}
+
+/// Whether adding an entry of `entry_size` bytes to a running total of
+/// `running_size` bytes still fits within `max_bytes`.
+fn fits_in_budget(running_size: usize, entry_size: usize, max_bytes: usize) -> bool {
+    running_size + entry_size <= max_bytes
+}
+
+/// The skip-or-keep decision for a single metric entry visited by
+/// [`StorageManager::snapshot_as_json_with_limit`], factored out of the
+/// visiting closure so it can be driven directly by tests without a real
+/// `Database`.
+///
+/// If `entry_size` (see [`serialized_entry_size`]) still fits within
+/// `max_bytes` given `running_size`, the entry is inserted into `snapshot`
+/// and `running_size` is advanced; otherwise the entry is dropped and
+/// `dropped_metrics` is incremented. Either way, iteration should continue
+/// with the next metric rather than aborting.
+fn accumulate_limited_entry<'a>(
+    snapshot: &mut BTreeMap<&'a str, BTreeMap<String, JsonValue>>,
+    running_size: &mut usize,
+    dropped_metrics: &mut u32,
+    category: &'a str,
+    metric_name: String,
+    value: JsonValue,
+    max_bytes: usize,
+) {
+    let entry_size = serialized_entry_size(&metric_name, &value);
+
+    if !fits_in_budget(*running_size, entry_size, max_bytes) {
+        *dropped_metrics += 1;
+        return;
+    }
+
+    *running_size += entry_size;
+    snapshot
+        .entry(category)
+        .or_insert_with(BTreeMap::new)
+        .insert(metric_name, value);
+}
+
+/// A filter describing which metrics to include in a
+/// [`StorageManager::snapshot_subset`] call.
+///
+/// An empty `categories` set (or an empty `name_patterns` list) places no
+/// restriction on that dimension; a metric only needs to satisfy the
+/// dimensions that are actually populated.
+#[derive(Debug, Default, Clone)]
+pub struct SnapshotFilter {
+    /// Allow-list of metric categories. If empty, metrics from any category
+    /// may match.
+    pub categories: BTreeSet<String>,
+    /// Allow-list of metric-name glob patterns, e.g. `"validation_*"`. A
+    /// pattern may end in one or more trailing `*` characters to match any
+    /// suffix (a bare `"*"` matches every name); otherwise it is matched
+    /// exactly. If empty, any metric name may match.
+    pub name_patterns: Vec<String>,
+}
+
+impl SnapshotFilter {
+    fn matches(&self, category: &str, name: &str) -> bool {
+        if !self.categories.is_empty() && !self.categories.contains(category) {
+            return false;
+        }
+
+        self.name_patterns.is_empty()
+            || self
+                .name_patterns
+                .iter()
+                .any(|pattern| Self::glob_match(pattern, name))
+    }
+
+    /// Whether this filter places no restriction at all, i.e. every metric
+    /// matches it.
+    fn is_unrestricted(&self) -> bool {
+        self.categories.is_empty() && self.name_patterns.is_empty()
+    }
+
+    fn glob_match(pattern: &str, name: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            // Trim any further trailing `*`s too, so "foo*", "foo**", ... and a
+            // bare "*" all mean "any name starting with (possibly nothing of) this prefix".
+            Some(prefix) => name.starts_with(prefix.trim_end_matches('*')),
+            None => pattern == name,
+        }
+    }
+}
+
 pub struct StorageManager;
 
 impl StorageManager {
@@ -31,12 +137,12 @@ impl StorageManager {
         store_name: &str,
         clear_store: bool,
     ) -> Option<JsonValue> {
-        let mut snapshot: HashMap<&str, HashMap<String, JsonValue>> = HashMap::new();
+        let mut snapshot: BTreeMap<&str, BTreeMap<String, JsonValue>> = BTreeMap::new();
 
         let mut snapshotter = |metric_name: &[u8], metric: &Metric| {
             let map = snapshot
                 .entry(metric.category())
-                .or_insert_with(HashMap::new);
+                .or_insert_with(BTreeMap::new);
             let metric_name = String::from_utf8_lossy(metric_name).into_owned();
             map.insert(metric_name, metric.as_json());
         };
@@ -56,6 +162,238 @@ impl StorageManager {
         }
     }
 
+    /// Get a snapshot of the given store, dropping metrics as needed to stay
+    /// within a maximum serialized payload size.
+    ///
+    /// A running total of the serialized size is kept as metrics are visited
+    /// (each metric's rendered `as_json()` length plus its key); once adding a
+    /// metric would push that total past `max_bytes`, the metric is skipped
+    /// and counted as dropped rather than aborting the whole snapshot. Note
+    /// that this total is an estimate: it doesn't account for the quotes,
+    /// colons, commas and category-wrapper braces the final `json!` output
+    /// adds around each entry, so the actual serialized payload will run a
+    /// little larger than `max_bytes`. Callers that need a hard ceiling
+    /// should pass a `max_bytes` with that overhead budgeted in.
+    ///
+    /// `clear_store` still clears the *entire* store's ping lifetime, not
+    /// just the metrics that made it into the returned snapshot — so a
+    /// metric dropped for exceeding `max_bytes` is lost, not carried over to
+    /// the next ping. Callers that care about that should record
+    /// `dropped_metrics` (e.g. as a `glean.error.invalid_overflow` error
+    /// metric) before clearing.
+    ///
+    /// ## Arguments:
+    ///
+    /// * `storage`: The database to get data from.
+    /// * `store_name`: The store name to snapshot.
+    /// * `clear_store`: Whether to clear the ping lifetime storage for this store.
+    /// * `max_bytes`: The maximum estimated serialized size, in bytes, the
+    ///   kept metrics may add up to (see the note on the estimate above).
+    ///
+    /// ## Return value:
+    ///
+    /// Returns the (possibly `None`, if nothing fit or the store was empty)
+    /// snapshot, along with a [`SnapshotInfo`] describing how large it ended
+    /// up and how many metrics were dropped to make it fit.
+    pub fn snapshot_as_json_with_limit(
+        &self,
+        storage: &Database,
+        store_name: &str,
+        clear_store: bool,
+        max_bytes: usize,
+    ) -> (Option<JsonValue>, SnapshotInfo) {
+        let mut snapshot: BTreeMap<&str, BTreeMap<String, JsonValue>> = BTreeMap::new();
+        let mut running_size = 0usize;
+        let mut dropped_metrics = 0u32;
+
+        let mut snapshotter = |metric_name: &[u8], metric: &Metric| {
+            let metric_name = String::from_utf8_lossy(metric_name).into_owned();
+            let value = metric.as_json();
+            accumulate_limited_entry(
+                &mut snapshot,
+                &mut running_size,
+                &mut dropped_metrics,
+                metric.category(),
+                metric_name,
+                value,
+                max_bytes,
+            );
+        };
+
+        storage.iter_store_from(Lifetime::Ping, &store_name, &mut snapshotter);
+        storage.iter_store_from(Lifetime::Application, &store_name, &mut snapshotter);
+        storage.iter_store_from(Lifetime::User, &store_name, &mut snapshotter);
+
+        if clear_store {
+            storage.clear_ping_lifetime_storage(store_name);
+        }
+
+        let info = SnapshotInfo {
+            size: running_size,
+            dropped_metrics,
+        };
+
+        if snapshot.is_empty() {
+            (None, info)
+        } else {
+            (Some(json!(snapshot)), info)
+        }
+    }
+
+    /// Write a snapshot of the given store directly to `writer`, keeping
+    /// peak memory proportional to the store's key index rather than to its
+    /// full decoded payload.
+    ///
+    /// Unlike [`snapshot_as_json`](Self::snapshot_as_json), this does not build a
+    /// `category -> { name -> value }` map of every metric up front. Instead it
+    /// first walks the store once per lifetime to build an index of which
+    /// `(metric name, lifetime)` pairs exist per category — decoding each
+    /// metric only long enough to read its category, not keeping the decoded
+    /// value around — and only afterwards serializes one category at a time,
+    /// re-reading (and this time keeping) just that category's values from the
+    /// database. That second pass re-walks the store once per lifetime for
+    /// every category, trading extra I/O for the lower peak memory, which is
+    /// the right side of that trade for stores holding large string-list or
+    /// event-style metrics that would otherwise all be decoded and held in
+    /// memory at once.
+    ///
+    /// ## Arguments:
+    ///
+    /// * `storage`: The database to get data from.
+    /// * `store_name`: The store name to snapshot.
+    /// * `clear_store`: Whether to clear the ping lifetime storage for this store
+    ///   once the snapshot has been written.
+    /// * `writer`: Where to write the serialized JSON to.
+    ///
+    /// ## Return value:
+    ///
+    /// Returns `true` if anything was written, `false` if the store was empty.
+    pub fn snapshot_to_writer<W: Write>(
+        &self,
+        storage: &Database,
+        store_name: &str,
+        clear_store: bool,
+        writer: W,
+    ) -> bool {
+        let mut index: BTreeMap<String, BTreeSet<(String, Lifetime)>> = BTreeMap::new();
+
+        for &lifetime in &[Lifetime::Ping, Lifetime::Application, Lifetime::User] {
+            let mut indexer = |metric_name: &[u8], metric: &Metric| {
+                let metric_name = String::from_utf8_lossy(metric_name).into_owned();
+                index
+                    .entry(metric.category().to_string())
+                    .or_insert_with(BTreeSet::new)
+                    .insert((metric_name, lifetime));
+            };
+            storage.iter_store_from(lifetime, &store_name, &mut indexer);
+        }
+
+        if index.is_empty() {
+            if clear_store {
+                storage.clear_ping_lifetime_storage(store_name);
+            }
+            return false;
+        }
+
+        let mut ser = Serializer::new(writer);
+        let mut top = ser
+            .serialize_map(Some(index.len()))
+            .expect("failed to start JSON object");
+
+        for (category, names) in &index {
+            let mut values: BTreeMap<String, JsonValue> = BTreeMap::new();
+
+            for &lifetime in &[Lifetime::Ping, Lifetime::Application, Lifetime::User] {
+                let mut snapshotter = |metric_name: &[u8], metric: &Metric| {
+                    if metric.category() != category {
+                        return;
+                    }
+                    let metric_name = String::from_utf8_lossy(metric_name).into_owned();
+                    if names.contains(&(metric_name.clone(), lifetime)) {
+                        values.insert(metric_name, metric.as_json());
+                    }
+                };
+                storage.iter_store_from(lifetime, &store_name, &mut snapshotter);
+            }
+
+            top.serialize_entry(category, &values)
+                .expect("failed to serialize category");
+        }
+
+        top.end().expect("failed to finish JSON object");
+
+        if clear_store {
+            storage.clear_ping_lifetime_storage(store_name);
+        }
+
+        true
+    }
+
+    /// Get a snapshot containing only the metrics matching `filter`.
+    ///
+    /// The snapshotter closure consults `filter` before inserting a metric
+    /// into the returned map, so a targeted diagnostic ping (for example,
+    /// only `glean.*` internal metrics) can be assembled without unrelated
+    /// metrics showing up in it.
+    ///
+    /// `Database` doesn't currently expose a way to clear only the
+    /// ping-lifetime entries matching a filter — only the existing
+    /// all-or-nothing [`clear_ping_lifetime_storage`](Database::clear_ping_lifetime_storage).
+    /// Calling that for a `filter` that excludes some metrics would destroy
+    /// exactly the unrelated, unmatched data this method exists to leave
+    /// alone, which is worse than not clearing at all. So until a selective
+    /// clear primitive exists on `Database`, `clear_store` here only takes
+    /// effect when `filter` is unrestricted (matches every metric), in which
+    /// case it's equivalent to [`snapshot_as_json`](Self::snapshot_as_json)'s
+    /// `clear_store`; for any narrower `filter` it is a no-op and the matched
+    /// metrics remain in storage after the snapshot is taken.
+    ///
+    /// ## Arguments:
+    ///
+    /// * `storage`: The database to get data from.
+    /// * `store_name`: The store name to snapshot.
+    /// * `clear_store`: Whether to clear the ping lifetime storage for this store;
+    ///   only honored when `filter` is unrestricted, see above.
+    /// * `filter`: The category/name filter metrics must match to be included.
+    ///
+    /// ## Return value:
+    ///
+    /// Returns the decoded metrics that matched `filter`, or `None` if none did.
+    pub fn snapshot_subset(
+        &self,
+        storage: &Database,
+        store_name: &str,
+        clear_store: bool,
+        filter: &SnapshotFilter,
+    ) -> Option<JsonValue> {
+        let mut snapshot: BTreeMap<&str, BTreeMap<String, JsonValue>> = BTreeMap::new();
+
+        let mut snapshotter = |metric_name: &[u8], metric: &Metric| {
+            let metric_name = String::from_utf8_lossy(metric_name).into_owned();
+            if !filter.matches(metric.category(), &metric_name) {
+                return;
+            }
+            let map = snapshot
+                .entry(metric.category())
+                .or_insert_with(BTreeMap::new);
+            map.insert(metric_name, metric.as_json());
+        };
+
+        storage.iter_store_from(Lifetime::Ping, &store_name, &mut snapshotter);
+        storage.iter_store_from(Lifetime::Application, &store_name, &mut snapshotter);
+        storage.iter_store_from(Lifetime::User, &store_name, &mut snapshotter);
+
+        if clear_store && filter.is_unrestricted() {
+            storage.clear_ping_lifetime_storage(store_name);
+        }
+
+        if snapshot.is_empty() {
+            None
+        } else {
+            Some(json!(snapshot))
+        }
+    }
+
     /// Get the current value of a single metric identified by name.
     ///
     /// This look for a value in stores for all lifetimes.
@@ -91,3 +429,173 @@ impl StorageManager {
         snapshot
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fits_in_budget_allows_exact_fit() {
+        assert!(fits_in_budget(0, 10, 10));
+        assert!(fits_in_budget(7, 3, 10));
+    }
+
+    #[test]
+    fn fits_in_budget_rejects_one_byte_over() {
+        assert!(!fits_in_budget(0, 11, 10));
+        assert!(!fits_in_budget(8, 3, 10));
+    }
+
+    #[test]
+    fn serialized_entry_size_counts_key_and_value() {
+        let value = json!("hi");
+        // key "name" (4 bytes) + serialized value `"hi"` (4 bytes).
+        assert_eq!(serialized_entry_size("name", &value), 8);
+    }
+
+    // These drive the skip/keep/size/dropped-count accounting of
+    // `snapshot_as_json_with_limit` end to end via `accumulate_limited_entry`,
+    // the same helper the real method's visiting closure calls for every
+    // metric. They don't go through `snapshot_as_json_with_limit` itself or a
+    // real `Database`, since this crate has no `Database`/`Metric`
+    // implementation to construct one against here; that would need covering
+    // in the full crate.
+    #[test]
+    fn accumulate_limited_entry_keeps_entries_that_fit() {
+        let mut snapshot: BTreeMap<&str, BTreeMap<String, JsonValue>> = BTreeMap::new();
+        let mut running_size = 0usize;
+        let mut dropped_metrics = 0u32;
+
+        accumulate_limited_entry(
+            &mut snapshot,
+            &mut running_size,
+            &mut dropped_metrics,
+            "glean",
+            "counter".to_string(),
+            json!(1),
+            1_000,
+        );
+
+        assert_eq!(dropped_metrics, 0);
+        assert_eq!(running_size, serialized_entry_size("counter", &json!(1)));
+        assert_eq!(
+            snapshot.get("glean").and_then(|m| m.get("counter")),
+            Some(&json!(1))
+        );
+    }
+
+    #[test]
+    fn accumulate_limited_entry_skips_and_continues_past_budget() {
+        let mut snapshot: BTreeMap<&str, BTreeMap<String, JsonValue>> = BTreeMap::new();
+        let mut running_size = 0usize;
+        let mut dropped_metrics = 0u32;
+        let max_bytes = 20;
+
+        // Small enough to fit: kept.
+        accumulate_limited_entry(
+            &mut snapshot,
+            &mut running_size,
+            &mut dropped_metrics,
+            "glean",
+            "aaaaa".to_string(),
+            json!(1),
+            max_bytes,
+        );
+        // Would blow the budget on its own: dropped, but iteration keeps
+        // going rather than aborting the whole snapshot.
+        accumulate_limited_entry(
+            &mut snapshot,
+            &mut running_size,
+            &mut dropped_metrics,
+            "glean",
+            "b".repeat(50),
+            json!(0),
+            max_bytes,
+        );
+        // Still fits in what's left of the budget after the drop: kept,
+        // proving the budget check runs per entry rather than latching shut
+        // once one entry has been dropped.
+        accumulate_limited_entry(
+            &mut snapshot,
+            &mut running_size,
+            &mut dropped_metrics,
+            "other",
+            "ccccc".to_string(),
+            json!(0),
+            max_bytes,
+        );
+
+        assert_eq!(dropped_metrics, 1);
+        assert_eq!(running_size, 12);
+        assert_eq!(
+            snapshot.get("glean").and_then(|m| m.get("aaaaa")),
+            Some(&json!(1))
+        );
+        assert_eq!(snapshot.get("glean").map(|m| m.len()), Some(1));
+        assert_eq!(
+            snapshot.get("other").and_then(|m| m.get("ccccc")),
+            Some(&json!(0))
+        );
+    }
+
+    #[test]
+    fn glob_match_exact() {
+        assert!(SnapshotFilter::glob_match("loaded", "loaded"));
+        assert!(!SnapshotFilter::glob_match("loaded", "loaded_page"));
+    }
+
+    #[test]
+    fn glob_match_trailing_star() {
+        assert!(SnapshotFilter::glob_match("validation_*", "validation_errors"));
+        assert!(SnapshotFilter::glob_match("validation_*", "validation_"));
+        assert!(!SnapshotFilter::glob_match("validation_*", "other_metric"));
+    }
+
+    #[test]
+    fn glob_match_bare_star_matches_everything() {
+        assert!(SnapshotFilter::glob_match("*", ""));
+        assert!(SnapshotFilter::glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn glob_match_multiple_trailing_stars() {
+        assert!(SnapshotFilter::glob_match("foo**", "foobar"));
+        assert!(SnapshotFilter::glob_match("foo**", "foo"));
+        assert!(!SnapshotFilter::glob_match("foo**", "barfoo"));
+    }
+
+    #[test]
+    fn snapshot_filter_matches_requires_both_dimensions() {
+        let filter = SnapshotFilter {
+            categories: vec!["glean".to_string()].into_iter().collect(),
+            name_patterns: vec!["validation_*".to_string()],
+        };
+
+        assert!(filter.matches("glean", "validation_errors"));
+        assert!(!filter.matches("other", "validation_errors"));
+        assert!(!filter.matches("glean", "unrelated_metric"));
+    }
+
+    #[test]
+    fn snapshot_filter_empty_dimensions_place_no_restriction() {
+        let filter = SnapshotFilter::default();
+        assert!(filter.matches("any_category", "any_name"));
+    }
+
+    #[test]
+    fn snapshot_filter_is_unrestricted_only_when_both_dimensions_are_empty() {
+        assert!(SnapshotFilter::default().is_unrestricted());
+
+        assert!(!SnapshotFilter {
+            categories: vec!["glean".to_string()].into_iter().collect(),
+            name_patterns: vec![],
+        }
+        .is_unrestricted());
+
+        assert!(!SnapshotFilter {
+            categories: BTreeSet::new(),
+            name_patterns: vec!["validation_*".to_string()],
+        }
+        .is_unrestricted());
+    }
+}